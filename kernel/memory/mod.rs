@@ -0,0 +1,48 @@
+//! Virtual memory management.
+
+pub mod paging;
+mod perms;
+
+pub use perms::{mark_executable, mark_unused, mark_writable};
+
+use x86_64::structures::paging::{PageTable, RecursivePageTable};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// The level 4 page table entry the bootloader recursively maps itself into.
+const RECURSIVE_INDEX: u64 = 510;
+
+/// Virtual address physical address 0 is mapped at, so the kernel can read/write any physical
+/// frame directly (e.g. to copy a page servicing a copy-on-write fault) without needing a
+/// temporary mapping. Set once during `init`.
+static mut PHYS_MEM_OFFSET: u64 = 0;
+
+/// Record where physical memory is mapped in the kernel's address space. Must be called once
+/// during boot, before anything in this module touches physical memory directly.
+pub fn init(phys_mem_offset: VirtAddr) {
+    unsafe { PHYS_MEM_OFFSET = phys_mem_offset.as_u64() };
+}
+
+/// Translate a physical address to the kernel virtual address it's mapped at.
+pub(crate) fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(unsafe { PHYS_MEM_OFFSET } + addr.as_u64())
+}
+
+/// Borrow the currently-active page table through the recursive mapping set up at boot.
+///
+/// # Safety
+///
+/// The caller must not hold another live borrow of the page table (e.g. via a concurrent call to
+/// this function) for the duration of the returned `RecursivePageTable`.
+pub(crate) unsafe fn active_page_table() -> RecursivePageTable<'static> {
+    let level_4_table_addr = VirtAddr::new_truncate(sign_extend(
+        (RECURSIVE_INDEX << 39) | (RECURSIVE_INDEX << 30) | (RECURSIVE_INDEX << 21) | (RECURSIVE_INDEX << 12),
+    ));
+    let level_4_table: &mut PageTable = &mut *(level_4_table_addr.as_mut_ptr());
+    RecursivePageTable::new(level_4_table).expect("level 4 table is not recursively mapped")
+}
+
+/// Sign-extend a 48-bit canonical virtual address built from recursive-mapping index bits into a
+/// full 64-bit canonical address.
+const fn sign_extend(addr: u64) -> u64 {
+    ((addr << 16) as i64 >> 16) as u64
+}
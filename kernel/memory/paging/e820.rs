@@ -19,8 +19,72 @@ extern "C" {
     static memory_map: [MemoryRegion; 32];
 }
 
-/// The Region Type value for a usable region.
+/// The Region Type values the E820 BIOS call uses in `MemoryRegion::region_type`.
 const E820_MEMORY_USABLE: u32 = 1;
+const E820_MEMORY_RESERVED: u32 = 2;
+const E820_MEMORY_ACPI_RECLAIMABLE: u32 = 3;
+const E820_MEMORY_ACPI_NVS: u32 = 4;
+const E820_MEMORY_BAD: u32 = 5;
+
+/// The ACPI 3.0 extended attributes bit that, if clear, means the whole entry should be ignored.
+/// Only meaningful on BIOSes that actually return the extended, 24-byte entry format; a legacy
+/// BIOS returning plain 20-byte entries never writes this dword at all, so whatever value the
+/// (pre-Rust) E820 collection code defaults it to shouldn't be read as an explicit "ignore".
+const ACPI_ATTR_VALID: u32 = 1 << 0;
+
+/// The ACPI 3.0 extended attributes bit marking a region as non-volatile memory.
+const ACPI_ATTR_NON_VOLATILE: u32 = 1 << 1;
+
+/// What kind of memory an E820 region is, so callers can tell usable RAM apart from memory that
+/// must be left alone (reserved, broken) or only reclaimed after ACPI tables have been parsed out
+/// of it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionType {
+    /// Free for general use.
+    Usable,
+    /// Holds ACPI tables; safe to reclaim for general use once those tables have been parsed.
+    AcpiReclaimable,
+    /// ACPI non-volatile storage, or memory the extended attributes flagged as non-volatile.
+    /// Never safe to treat as general-purpose RAM.
+    AcpiNvs,
+    /// Reserved by firmware/hardware; never usable.
+    Reserved,
+    /// Reported as faulty; never usable.
+    BadMemory,
+    /// A region type value the E820 spec doesn't define. Treated as conservatively as `Reserved`.
+    Other(u32),
+}
+
+impl RegionType {
+    fn from_raw(raw: u32) -> RegionType {
+        match raw {
+            E820_MEMORY_USABLE => RegionType::Usable,
+            E820_MEMORY_RESERVED => RegionType::Reserved,
+            E820_MEMORY_ACPI_RECLAIMABLE => RegionType::AcpiReclaimable,
+            E820_MEMORY_ACPI_NVS => RegionType::AcpiNvs,
+            E820_MEMORY_BAD => RegionType::BadMemory,
+            other => RegionType::Other(other),
+        }
+    }
+
+    /// Higher is more restrictive. When overlapping regions disagree on type, the most
+    /// restrictive one wins, rather than collapsing everything to usable-or-not.
+    fn restrictiveness(self) -> u8 {
+        match self {
+            RegionType::Usable => 0,
+            RegionType::AcpiReclaimable => 1,
+            RegionType::AcpiNvs => 2,
+            RegionType::Reserved => 3,
+            RegionType::BadMemory => 4,
+            RegionType::Other(_) => 3,
+        }
+    }
+
+    /// Whether this region is free for general-purpose allocation right now.
+    pub fn is_usable(self) -> bool {
+        self == RegionType::Usable
+    }
+}
 
 /// Represents an entry in the list of memory regions generated by the E820 BIOS call
 #[derive(Clone, Copy, Debug)]
@@ -53,6 +117,10 @@ impl MemoryRegion {
 
 /// Safe wrapper around the info from E820.
 pub struct E820Info {
+    /// Every classified region, as `(start_frame, end_frame)` (inclusive) plus its winning type.
+    classified: Vec<(usize, usize, RegionType)>,
+    /// The usable-only subset of `classified`, in the same `(start_frame, end_frame)` form the
+    /// frame allocator wants.
     regions: Vec<(usize, usize)>,
 }
 
@@ -60,8 +128,8 @@ impl E820Info {
     /// Read the information from the E820 `memory_map` and parse into a safe wrapper.
     pub fn read() -> Self {
         // e820 regions in the memory map can overlap. Worse, overlapping regions can have
-        // different usability info. Here we will be conservative and say that a portion of memory
-        // is usable only if all overlapping regions are marked usable.
+        // different usability info. Here we resolve overlaps conservatively: the winning type for
+        // any overlapping portion of memory is the most restrictive of the types that cover it.
 
         // Also, this function is optimized for readability. Since we only have 32 regions at most,
         // performance is not an issue.
@@ -72,7 +140,22 @@ impl E820Info {
             .iter()
             .take(unsafe { memory_map_count as usize })
             .filter(|region| region.len() > 0)
-            .map(|region| (region.start_addr(), region.end_addr(), region.region_type))
+            // ACPI 3.0 extended attributes, bit 0: if clear, the BIOS is telling us to ignore this
+            // entry entirely. Only trust that when the BIOS actually returned the extended,
+            // 24-byte entry format — there's no per-entry size tracked this far from the raw BIOS
+            // call, so approximate it as "the dword isn't all zero": a real ACPI 3.0 BIOS always
+            // sets bit 0 on every normal entry, so an all-zero dword is far more likely an
+            // untouched legacy field than a deliberately-invalidated region.
+            .filter(|region| region.acpi == 0 || region.acpi & ACPI_ATTR_VALID != 0)
+            .map(|region| {
+                let mut ty = RegionType::from_raw(region.region_type);
+                // Bit 1: the region is non-volatile. A region reported as usable is surfaced as
+                // `AcpiNvs` instead; other region types keep the type they were reported with.
+                if region.acpi & ACPI_ATTR_NON_VOLATILE != 0 && ty == RegionType::Usable {
+                    ty = RegionType::AcpiNvs;
+                }
+                (region.start_addr(), region.end_addr(), ty)
+            })
             .collect();
 
         // To make life easy, we will break up partially overlapping regions so that if two regions
@@ -104,30 +187,28 @@ impl E820Info {
         // Sort by start of region
         info.sort_by_key(|&(start, _, _)| start);
 
-        // Finally, find out if each region is useable.
-        let mut regions = Vec::new();
+        // Finally, find the winning (most restrictive) type for each slice of the address space.
+        let mut classified = Vec::new();
         for start in endpoints.into_iter() {
             let same_start: Vec<_> = info.drain_filter(|&mut (s, _, _)| start == s).collect();
-            let all_usable = same_start
-                .iter()
-                .all(|&(s, e, ty)| s < e && ty == E820_MEMORY_USABLE);
 
-            if same_start.len() > 0 && all_usable {
+            if let Some(&(s, e, _)) = same_start.iter().find(|&&(s, e, _)| s < e) {
+                let winner = same_start
+                    .iter()
+                    .filter(|&&(s, e, _)| s < e)
+                    .map(|&(_, _, ty)| ty)
+                    .max_by_key(|ty| ty.restrictiveness())
+                    .unwrap();
+
                 // (same_start() will be empty for the last endpoint)
-                regions.push(
-                    same_start
-                        .into_iter()
-                        .next()
-                        .map(|(s, e, _)| (s, e - 1))
-                        .unwrap(),
-                );
+                classified.push((s, e - 1, winner));
             }
         }
 
         // Convert to frame boundaries
-        let regions = regions
+        let classified = classified
             .into_iter()
-            .map(|(s_bytes, e_bytes)| {
+            .map(|(s_bytes, e_bytes, ty)| {
                 // Round up to nearest page boundary
                 let s_page = PhysAddr::new(s_bytes).align_up(Size4KiB::SIZE).as_u64();
 
@@ -137,24 +218,42 @@ impl E820Info {
                 (
                     (s_page / Size4KiB::SIZE) as usize,
                     (e_page / Size4KiB::SIZE) as usize,
+                    ty,
                 )
             })
-            .filter(|(s, e)| s <= e)
+            .filter(|&(s, e, _)| s <= e)
+            .collect::<Vec<_>>();
+
+        let regions = classified
+            .iter()
+            .filter(|&&(_, _, ty)| ty.is_usable())
+            .map(|&(s, e, _)| (s, e))
             .collect();
 
-        E820Info { regions }
+        E820Info {
+            classified,
+            regions,
+        }
     }
 
-    /// Compute the number of physical pages available.
+    /// Compute the number of usable physical pages available.
     pub fn num_phys_pages(&self) -> usize {
         self.regions
             .iter()
             .map(|(start, end)| end - start + 1)
             .sum()
     }
+
+    /// Every classified region as `(start_frame, end_frame, type)`, inclusive, in address order.
+    /// Includes non-usable memory (reserved, ACPI, bad) so callers can e.g. reclaim
+    /// `AcpiReclaimable` regions after parsing ACPI tables out of them, or steer clear of
+    /// `AcpiNvs`/`Reserved` ranges entirely.
+    pub fn regions_by_type(&self) -> &[(usize, usize, RegionType)] {
+        &self.classified
+    }
 }
 
-// Allows iterating over regions :)
+// Allows iterating over the usable regions :)
 impl Deref for E820Info {
     type Target = [(usize, usize)];
 
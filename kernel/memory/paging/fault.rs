@@ -0,0 +1,304 @@
+//! Demand paging and copy-on-write for user `VirtualMemoryRegion`s, driven by `#PF`.
+//!
+//! A region registered here is mapped lazily: nothing backs its pages until they're actually
+//! touched, at which point `handle_page_fault` allocates a frame and maps it with the region's
+//! intended flags. Copy-on-write reuses the same not-quite-mapped bookkeeping: a COW page is
+//! mapped read-only with its *eventual* (post-copy) flags recorded on the side, so a write fault
+//! to it is serviced the same way a first touch is, just with a frame copy first.
+
+use alloc::collections::BTreeMap;
+
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::paging::{Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::cap::{ResourceHandle, VirtualMemoryRegion};
+
+use super::frame_allocator::{self, FrameAllocator};
+use crate::memory::{active_page_table, phys_to_virt, sign_extend, RECURSIVE_INDEX};
+
+/// Bookkeeping for a page that isn't backed by its final frame yet.
+#[derive(Clone, Copy)]
+struct PendingPage {
+    /// The flags the page should be mapped with once it's actually populated.
+    flags: PageTableFlags,
+    /// Whether this is a copy-on-write page (mapped read-only, sharing a frame with another
+    /// mapping) rather than a plain not-yet-touched demand-paged one.
+    cow: bool,
+}
+
+/// Pages that are either demand-paged (not yet mapped at all) or copy-on-write (mapped read-only,
+/// pending a private copy), keyed by page-aligned virtual address. A page present here and NOT
+/// actually mapped is demand paging; present here and mapped read-only is COW.
+static mut PENDING: Option<BTreeMap<u64, PendingPage>> = None;
+
+/// Reference counts for frames shared by copy-on-write mappings, keyed by the frame's physical
+/// start address. The last holder to write to a COW page keeps the original frame instead of
+/// copying it away from itself.
+static mut COW_REFCOUNTS: Option<BTreeMap<u64, usize>> = None;
+
+fn pending() -> &'static mut BTreeMap<u64, PendingPage> {
+    unsafe { PENDING.get_or_insert_with(BTreeMap::new) }
+}
+
+fn cow_refcounts() -> &'static mut BTreeMap<u64, usize> {
+    unsafe { COW_REFCOUNTS.get_or_insert_with(BTreeMap::new) }
+}
+
+/// Register `region` for demand paging: none of its pages are mapped yet, but the first access to
+/// any of them should fault in a fresh frame with `flags`.
+pub fn register_lazy(region: ResourceHandle, flags: PageTableFlags) {
+    for_each_page(region, |addr| {
+        pending().insert(addr, PendingPage { flags, cow: false });
+    });
+}
+
+/// Share `region`'s already-populated writable pages copy-on-write: both this mapping and the
+/// clone that will be pointed at the same frames see them read-only, with the shared frame's
+/// reference count bumped accordingly. Unpopulated pages are left alone — they'll fault in their
+/// own private frame independently on each side.
+///
+/// Only updates the current address space's view; wiring a second address space up to the same
+/// frames is the caller's job once there is a per-process page table to clone into.
+pub fn share_cow(region: ResourceHandle) {
+    let mut page_table = unsafe { active_page_table() };
+
+    for_each_page(region, |addr| {
+        let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(addr));
+        let frame = match page_table.translate_page(page) {
+            Ok(frame) => frame,
+            Err(_) => return, // not populated yet; nothing to share
+        };
+
+        // Read the page's own level 1 entry through the recursive mapping: read-only pages can
+        // be shared as they are and are left alone.
+        let p1_entry = sign_extend(
+            (RECURSIVE_INDEX << 39)
+                | (u64::from(page.p4_index()) << 30)
+                | (u64::from(page.p3_index()) << 21)
+                | (u64::from(page.p2_index()) << 12),
+        ) + u64::from(page.p1_index()) * 8;
+        let raw_entry = unsafe { *(p1_entry as *const u64) };
+        if !PageTableFlags::from_bits_truncate(raw_entry).contains(PageTableFlags::WRITABLE) {
+            return;
+        }
+
+        let read_only = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE;
+        unsafe {
+            page_table
+                .update_flags(page, read_only)
+                .expect("page was just translated, so it is mapped")
+                .flush();
+        }
+
+        pending().insert(
+            addr,
+            PendingPage {
+                flags: read_only | PageTableFlags::WRITABLE,
+                cow: true,
+            },
+        );
+
+        *cow_refcounts()
+            .entry(frame.start_address().as_u64())
+            .or_insert(1) += 1;
+    });
+}
+
+/// What the caller should do after `handle_page_fault` returns.
+pub enum FaultOutcome {
+    /// The fault was demand paging or COW and has been serviced; resume the faulting instruction.
+    Serviced,
+    /// A genuine protection violation — an address with no registration at all, or a write to a
+    /// page that is neither writable nor COW. The faulting process should be killed. Also
+    /// returned when no free frame is left to service an otherwise legitimate fault.
+    Violation,
+}
+
+/// Service a `#PF` at `fault_addr` (the value read from `CR2`) with the given CPU-provided
+/// `error_code`, using `frames` to back any newly-populated page.
+pub fn handle_page_fault(
+    fault_addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+    frames: &mut FrameAllocator,
+) -> FaultOutcome {
+    let page: Page<Size4KiB> = Page::containing_address(fault_addr);
+    let key = page.start_address().as_u64();
+
+    let was_present = error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
+    let was_write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+
+    if !was_present {
+        // First touch of a registered-but-unpopulated (not-COW) page: allocate and map it.
+        let info = match pending().get(&key).copied() {
+            Some(info) if !info.cow => info,
+            _ => return FaultOutcome::Violation,
+        };
+
+        return match map_fresh_frame(page, info, frames) {
+            Some(()) => FaultOutcome::Serviced,
+            None => FaultOutcome::Violation,
+        };
+    }
+
+    if was_write {
+        // Write to a read-only page: only legitimate if it's COW.
+        let info = match pending().get(&key).copied() {
+            Some(info) if info.cow => info,
+            _ => return FaultOutcome::Violation,
+        };
+
+        let mut page_table = unsafe { active_page_table() };
+        let old_frame = page_table
+            .translate_page(page)
+            .expect("COW page is registered, so it must already be mapped");
+
+        // If we're the only remaining holder of `old_frame`, there's nothing to copy: just take
+        // it over and remap it writable in place, same as any other solely-owned frame.
+        if is_sole_cow_holder(old_frame) {
+            cow_refcounts().remove(&old_frame.start_address().as_u64());
+            unsafe {
+                page_table
+                    .update_flags(page, info.flags)
+                    .expect("page was just translated")
+                    .flush();
+            }
+
+            pending().remove(&key);
+            return FaultOutcome::Serviced;
+        }
+
+        let new_frame = match frames.allocate_frame() {
+            Some(frame) => frame,
+            None => return FaultOutcome::Violation,
+        };
+
+        unsafe { copy_frame(old_frame, new_frame) };
+        release_cow_frame(old_frame, frames);
+
+        unsafe {
+            let (_, flush) = page_table.unmap(page).expect("page was just translated");
+            flush.flush();
+            page_table
+                .map_to(page, new_frame, info.flags, frames)
+                .expect("page was just unmapped")
+                .flush();
+        }
+
+        pending().remove(&key);
+        return FaultOutcome::Serviced;
+    }
+
+    FaultOutcome::Violation
+}
+
+/// Whether `frame` has exactly one remaining COW holder (this fault's mapping). A frame that was
+/// never shared (no entry in `COW_REFCOUNTS`) is not a sole *COW* holder in this sense — there's
+/// no reference to release, so the normal copy path's `release_cow_frame` no-op applies instead.
+fn is_sole_cow_holder(frame: PhysFrame<Size4KiB>) -> bool {
+    cow_refcounts().get(&frame.start_address().as_u64()) == Some(&1)
+}
+
+/// Eagerly back a page registered via [`register_lazy`], instead of waiting for the first real
+/// access to fault it in. Used when a region's content has to be written into it right away (e.g.
+/// the file-backed part of an ELF segment), so the write doesn't have to go through a fault.
+///
+/// # Panics
+///
+/// Panics if `addr`'s page was never registered via `register_lazy`, is a COW page, or is out of
+/// physical memory.
+pub fn populate_now(addr: VirtAddr, frames: &mut FrameAllocator) {
+    let page: Page<Size4KiB> = Page::containing_address(addr);
+    let info = pending()
+        .get(&page.start_address().as_u64())
+        .copied()
+        .filter(|info| !info.cow)
+        .expect("page was not registered for demand paging");
+
+    map_fresh_frame(page, info, frames).expect("out of physical memory while populating a page");
+}
+
+/// Back `page` (registered in `PENDING` as `info`, not yet mapped) with a freshly allocated,
+/// zeroed frame. Shared by `handle_page_fault`'s demand-paging branch and `populate_now`, so
+/// there's one place that allocates, maps and zeroes a not-yet-populated page.
+fn map_fresh_frame(page: Page<Size4KiB>, info: PendingPage, frames: &mut FrameAllocator) -> Option<()> {
+    let frame = frames.allocate_frame()?;
+
+    let mut page_table = unsafe { active_page_table() };
+    unsafe {
+        page_table
+            .map_to(page, frame, info.flags, frames)
+            .expect("page was registered but not yet mapped")
+            .flush();
+        zero_frame(frame);
+    }
+
+    pending().remove(&page.start_address().as_u64());
+    Some(())
+}
+
+/// Zero a freshly allocated frame before handing it to user code, so it never exposes whatever was
+/// physically there before (e.g. another process's freed page).
+unsafe fn zero_frame(frame: PhysFrame<Size4KiB>) {
+    let dst = phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+    core::ptr::write_bytes(dst, 0, Size4KiB::SIZE as usize);
+}
+
+/// The `#PF` IDT entry point: read the faulting address out of `cr2`, service it the same way
+/// `handle_page_fault` does, and kill the process on a genuine violation.
+///
+/// Register this as the IDT's page-fault handler during boot, the same way `user::init` points
+/// `LSTAR` at `handle_syscall` for syscalls.
+///
+/// # Panics
+///
+/// Panics on a genuine [`FaultOutcome::Violation`] — there's no per-process teardown yet, so a bad
+/// access takes down the kernel instead of just the offending process.
+pub extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let fault_addr = Cr2::read();
+    match handle_page_fault(fault_addr, error_code, frame_allocator::frame_allocator()) {
+        FaultOutcome::Serviced => {}
+        FaultOutcome::Violation => panic!("page fault at {:?}: {:#?}", fault_addr, stack_frame),
+    }
+}
+
+/// Drop one reference to a COW frame, freeing it once the last holder has copied it away.
+fn release_cow_frame(frame: PhysFrame<Size4KiB>, frames: &mut FrameAllocator) {
+    let key = frame.start_address().as_u64();
+    let refcounts = cow_refcounts();
+    match refcounts.get_mut(&key) {
+        Some(count) if *count > 1 => *count -= 1,
+        Some(_) => {
+            refcounts.remove(&key);
+            frames.deallocate_frame(frame);
+        }
+        None => {
+            // Not actually shared (refcount never bumped, e.g. a single fault on a page that was
+            // never cloned); nothing to release.
+        }
+    }
+}
+
+/// Copy a physical frame's contents through the kernel's physical memory mapping.
+unsafe fn copy_frame(from: PhysFrame<Size4KiB>, to: PhysFrame<Size4KiB>) {
+    let src = phys_to_virt(from.start_address()).as_ptr::<u8>();
+    let dst = phys_to_virt(to.start_address()).as_mut_ptr::<u8>();
+    core::ptr::copy_nonoverlapping(src, dst, Size4KiB::SIZE as usize);
+}
+
+/// Call `f` with the page-aligned start address of every page in `region`.
+fn for_each_page(region: ResourceHandle, mut f: impl FnMut(u64)) {
+    region.with(|cap| {
+        let region = cap_unwrap!(VirtualMemoryRegion(cap));
+        let start = VirtAddr::from_ptr(region.start());
+        let num_pages = region.len() / Size4KiB::SIZE as usize;
+
+        for i in 0..num_pages {
+            f((start + i as u64 * Size4KiB::SIZE).as_u64());
+        }
+    });
+}
@@ -0,0 +1,164 @@
+//! A physical frame allocator over the usable regions `E820Info` reports.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use x86_64::{
+    structures::paging::{PageSize, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+use super::e820::E820Info;
+
+/// Hands out 4 KiB physical frames from the usable ranges `E820Info` found, tracked with one bit
+/// per frame (0 = free, 1 = in use).
+///
+/// Frame numbers are translated to/from bit indices by walking the usable regions in order, so
+/// bit `i` is the `i`th usable frame across all regions, not physical frame number `i`.
+pub struct FrameAllocator {
+    used: Vec<u64>,
+    regions: Vec<(usize, usize)>,
+    /// Where the next `allocate_frame` scan starts, so repeated allocations don't re-scan
+    /// already-exhausted low bits every time.
+    next_bit: usize,
+}
+
+impl FrameAllocator {
+    /// Build an allocator over `e820`'s usable regions. `reserved` is an iterator of
+    /// `(start_frame, end_frame)` ranges (inclusive, same units as `E820Info`'s regions) that are
+    /// already spoken for — e.g. the loaded kernel image and the E820 table itself — and so are
+    /// pre-marked in use and will never be handed out.
+    pub fn new(e820: &E820Info, reserved: impl Iterator<Item = (usize, usize)>) -> Self {
+        let num_bits = e820.num_phys_pages();
+        let used = vec![0u64; (num_bits + 63) / 64];
+        let regions: Vec<_> = e820.iter().copied().collect();
+
+        let mut allocator = FrameAllocator {
+            used,
+            regions,
+            next_bit: 0,
+        };
+
+        for (start, end) in reserved {
+            for frame in start..=end {
+                if let Some(bit) = allocator.bit_for_frame(frame) {
+                    allocator.set_used(bit);
+                }
+            }
+        }
+
+        allocator
+    }
+
+    /// The total number of frames this allocator manages (free or not).
+    fn num_bits(&self) -> usize {
+        self.regions.iter().map(|&(s, e)| e - s + 1).sum()
+    }
+
+    /// The bit index for physical frame number `frame`, if it falls within one of this
+    /// allocator's usable regions.
+    fn bit_for_frame(&self, frame: usize) -> Option<usize> {
+        let mut bit = 0;
+        for &(start, end) in &self.regions {
+            if frame >= start && frame <= end {
+                return Some(bit + (frame - start));
+            }
+            bit += end - start + 1;
+        }
+        None
+    }
+
+    /// The physical frame that bit index `bit` stands for.
+    fn frame_for_bit(&self, bit: usize) -> PhysFrame<Size4KiB> {
+        let mut remaining = bit;
+        for &(start, end) in &self.regions {
+            let len = end - start + 1;
+            if remaining < len {
+                let frame_num = start + remaining;
+                let addr = PhysAddr::new((frame_num * Size4KiB::SIZE as usize) as u64);
+                return PhysFrame::from_start_address(addr).unwrap();
+            }
+            remaining -= len;
+        }
+        panic!("bit index {} out of range", bit);
+    }
+
+    fn is_used(&self, bit: usize) -> bool {
+        self.used[bit / 64] & (1 << (bit % 64)) != 0
+    }
+
+    fn set_used(&mut self, bit: usize) {
+        self.used[bit / 64] |= 1 << (bit % 64);
+    }
+
+    fn set_free(&mut self, bit: usize) {
+        self.used[bit / 64] &= !(1 << (bit % 64));
+    }
+
+    /// Hand out a free physical frame, marking it in use. Returns `None` once every usable frame
+    /// is allocated.
+    pub fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let num_bits = self.num_bits();
+        for offset in 0..num_bits {
+            let bit = (self.next_bit + offset) % num_bits;
+            if !self.is_used(bit) {
+                self.set_used(bit);
+                self.next_bit = bit + 1;
+                return Some(self.frame_for_bit(bit));
+            }
+        }
+        None
+    }
+
+    /// Return a previously-allocated frame to the free pool.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `frame` doesn't fall in one of this allocator's usable regions.
+    pub fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let frame_num = (frame.start_address().as_u64() / Size4KiB::SIZE) as usize;
+        let bit = self
+            .bit_for_frame(frame_num)
+            .expect("frame is not in a usable E820 region");
+        self.set_free(bit);
+    }
+
+    /// Reserve a specific frame ahead of time, so a later `allocate_frame` never hands it out.
+    /// For backing a region whose physical address is already fixed.
+    ///
+    /// Does nothing if `frame` isn't in a usable region (nothing to reserve).
+    pub fn reserve_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let frame_num = (frame.start_address().as_u64() / Size4KiB::SIZE) as usize;
+        if let Some(bit) = self.bit_for_frame(frame_num) {
+            self.set_used(bit);
+        }
+    }
+}
+
+/// The kernel's single physical frame allocator, covering every usable E820 region. Set once
+/// during boot by `init`.
+static mut FRAME_ALLOCATOR: Option<FrameAllocator> = None;
+
+/// Build the global frame allocator over `e820`'s usable regions, reserving `reserved` up front.
+/// Must be called once during boot, before `map_region` or the page fault handler can back
+/// anything with a real frame.
+pub fn init(e820: &E820Info, reserved: impl Iterator<Item = (usize, usize)>) {
+    unsafe { FRAME_ALLOCATOR = Some(FrameAllocator::new(e820, reserved)) };
+}
+
+/// Borrow the global frame allocator.
+///
+/// # Panics
+///
+/// Panics if `init` hasn't run yet.
+pub fn frame_allocator() -> &'static mut FrameAllocator {
+    unsafe { FRAME_ALLOCATOR.as_mut().expect("frame_allocator::init was not called") }
+}
+
+// Lets a `FrameAllocator` back `Mapper::map_to` calls directly (e.g. when the page fault handler
+// creates a new mapping), without a separate adapter type.
+unsafe impl x86_64::structures::paging::FrameAllocator<Size4KiB> for FrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        FrameAllocator::allocate_frame(self)
+    }
+}
@@ -0,0 +1,35 @@
+//! Page table plumbing: parsing the E820 memory map and walking page table entries.
+
+pub mod e820;
+pub mod fault;
+pub mod frame_allocator;
+
+use x86_64::structures::paging::{Mapper, Page, PageSize, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::cap::{ResourceHandle, VirtualMemoryRegion};
+
+use super::active_page_table;
+
+/// Re-walk every page table entry backing `region` and overwrite it with `flags`.
+///
+/// # Panics
+///
+/// Panics if any page in `region` is not currently mapped.
+pub(crate) fn remap_pages(region: ResourceHandle, flags: PageTableFlags) {
+    region.with(|cap| {
+        let region = cap_unwrap!(VirtualMemoryRegion(cap));
+        let start = VirtAddr::from_ptr(region.start());
+        let num_pages = region.len() / Size4KiB::SIZE as usize;
+
+        let mut page_table = unsafe { active_page_table() };
+        for i in 0..num_pages {
+            let page: Page<Size4KiB> =
+                Page::containing_address(start + i as u64 * Size4KiB::SIZE);
+            page_table
+                .update_flags(page, flags)
+                .expect("page in region is not mapped")
+                .flush();
+        }
+    });
+}
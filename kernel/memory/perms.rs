@@ -0,0 +1,59 @@
+//! W^X permission transitions for user `VirtualMemoryRegion`s.
+//!
+//! A region is either being written to (`writable`), ready to run (`executable`), or torn down
+//! (`unused`); a page is never both writable and executable at the same time.
+
+use x86_64::structures::paging::{Mapper, Page, PageSize, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::cap::{ResourceHandle, VirtualMemoryRegion};
+
+use super::active_page_table;
+use super::paging::{frame_allocator, remap_pages};
+
+/// Make every page in `region` present, writable, and non-executable.
+///
+/// This is the state a region should be in while its contents (code, data, BSS) are still being
+/// written; see [`mark_executable`] for the other half of the transition.
+pub fn mark_writable(region: ResourceHandle) {
+    remap_pages(
+        region,
+        PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::USER_ACCESSIBLE
+            | PageTableFlags::NO_EXECUTE,
+    );
+}
+
+/// Make every page in `region` present, read-only, and executable.
+///
+/// Call this once a region's final contents are in place and it's ready to be run (e.g. after
+/// `load_user_code_section` finishes copying in an ELF segment's bytes).
+pub fn mark_executable(region: ResourceHandle) {
+    remap_pages(
+        region,
+        PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE,
+    );
+}
+
+/// Tear a region down: unmap every page and return its backing frame to the frame allocator.
+///
+/// # Panics
+///
+/// Panics if any page in `region` is not currently mapped.
+pub fn mark_unused(region: ResourceHandle) {
+    region.with(|cap| {
+        let region = cap_unwrap!(VirtualMemoryRegion(cap));
+        let start = VirtAddr::from_ptr(region.start());
+        let num_pages = region.len() / Size4KiB::SIZE as usize;
+
+        let mut page_table = unsafe { active_page_table() };
+        let frames = frame_allocator::frame_allocator();
+        for i in 0..num_pages {
+            let page: Page<Size4KiB> = Page::containing_address(start + i as u64 * Size4KiB::SIZE);
+            let (frame, flush) = unsafe { page_table.unmap(page) }.expect("page in region is not mapped");
+            flush.flush();
+            frames.deallocate_frame(frame);
+        }
+    });
+}
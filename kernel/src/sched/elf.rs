@@ -0,0 +1,213 @@
+//! Minimal ELF64 parsing, just enough to load a static user-mode executable.
+
+use core::mem::size_of;
+
+use alloc::vec::Vec;
+
+/// `e_ident[0..4]`: the ELF magic number.
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+
+/// `e_ident[4]`: 64-bit objects.
+const ELFCLASS64: u8 = 2;
+
+/// `e_ident[5]`: little-endian.
+const ELFDATA2LSB: u8 = 1;
+
+/// `e_type`: executable.
+const ET_EXEC: u16 = 2;
+
+/// `e_type`: position-independent (shared object / PIE).
+const ET_DYN: u16 = 3;
+
+/// `e_machine`: x86-64.
+const EM_X86_64: u16 = 62;
+
+/// `p_type`: a loadable segment.
+pub const PT_LOAD: u32 = 1;
+
+/// `p_flags`: segment is executable.
+pub const PF_X: u32 = 1 << 0;
+
+/// `p_flags`: segment is writable.
+pub const PF_W: u32 = 1 << 1;
+
+/// Reasons an image failed to parse as a loadable ELF64 executable.
+#[derive(Clone, Copy, Debug)]
+pub enum ElfError {
+    /// Image is too short to hold an ELF64 header.
+    TooShort,
+    /// `e_ident` doesn't start with the ELF magic number.
+    BadMagic,
+    /// Not a 64-bit object.
+    WrongClass,
+    /// Not little-endian.
+    WrongEndianness,
+    /// Not `ET_EXEC` or `ET_DYN`.
+    WrongType,
+    /// Not built for x86-64.
+    WrongMachine,
+    /// A program header lies outside the image.
+    TruncatedProgramHeader,
+    /// `e_phentsize` doesn't match the size of a `ProgramHeader`, so the file's spacing between
+    /// entries can't be trusted to leave room for the fields we read out of each one.
+    WrongProgramHeaderSize,
+    /// A `PT_LOAD` segment's `p_filesz` exceeds its `p_memsz`, i.e. it claims more on-disk bytes
+    /// than it has room for in memory. Trusting this would underflow the BSS length (`p_memsz -
+    /// p_filesz`) computed from it.
+    SegmentFileszExceedsMemsz,
+    /// A `PT_LOAD` segment's file-backed bytes (`p_offset..p_offset + p_filesz`) don't fit inside
+    /// the image.
+    SegmentOutOfBounds,
+    /// A `PT_LOAD` segment's `p_vaddr`/`p_memsz` would overflow when rounded out to whole pages.
+    /// Trusting this would overflow the page-count arithmetic callers derive from `p_memsz`.
+    SegmentSizeOverflow,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct Elf64Ehdr {
+    e_ident: [u8; 16],
+    e_type: u16,
+    e_machine: u16,
+    e_version: u32,
+    e_entry: u64,
+    e_phoff: u64,
+    e_shoff: u64,
+    e_flags: u32,
+    e_ehsize: u16,
+    e_phentsize: u16,
+    e_phnum: u16,
+    e_shentsize: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+/// A `PT_LOAD` program header: a contiguous chunk of the image that should be mapped into the
+/// process's address space.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub p_flags: u32,
+    pub p_offset: u64,
+    pub p_vaddr: u64,
+    pub p_paddr: u64,
+    pub p_filesz: u64,
+    pub p_memsz: u64,
+    pub p_align: u64,
+}
+
+/// A parsed ELF64 executable image, borrowed from the bytes it was parsed out of.
+pub struct Elf64Image<'a> {
+    image: &'a [u8],
+    header: Elf64Ehdr,
+}
+
+impl<'a> Elf64Image<'a> {
+    /// Validate `image` as an ELF64 executable for this machine and parse its header.
+    pub fn parse(image: &'a [u8]) -> Result<Self, ElfError> {
+        if image.len() < size_of::<Elf64Ehdr>() {
+            return Err(ElfError::TooShort);
+        }
+
+        // SAFETY: we just checked that `image` is at least `size_of::<Elf64Ehdr>()` bytes, and
+        // `Elf64Ehdr` has no invalid bit patterns (it's all integers and a byte array).
+        let header = unsafe { (image.as_ptr() as *const Elf64Ehdr).read_unaligned() };
+
+        if header.e_ident[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if header.e_ident[4] != ELFCLASS64 {
+            return Err(ElfError::WrongClass);
+        }
+        if header.e_ident[5] != ELFDATA2LSB {
+            return Err(ElfError::WrongEndianness);
+        }
+        if header.e_type != ET_EXEC && header.e_type != ET_DYN {
+            return Err(ElfError::WrongType);
+        }
+        if header.e_machine != EM_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+
+        Ok(Elf64Image { image, header })
+    }
+
+    /// The RIP execution should start at.
+    pub fn entry(&self) -> u64 {
+        self.header.e_entry
+    }
+
+    /// The image's validated `PT_LOAD` program headers, in file order.
+    ///
+    /// Every returned header is checked against `self.image` up front, so callers (and
+    /// `segment_data`) can trust `p_filesz <= p_memsz`, `p_offset..p_offset + p_filesz` to be in
+    /// bounds, and `p_vaddr`/`p_memsz` rounded out to whole pages to fit in a `u64` without
+    /// overflow, all without re-checking.
+    pub fn load_segments(&self) -> Result<Vec<ProgramHeader>, ElfError> {
+        let phoff = self.header.e_phoff as usize;
+        let phentsize = self.header.e_phentsize as usize;
+        let phnum = self.header.e_phnum as usize;
+        let image = self.image;
+
+        // We read each entry as a `ProgramHeader` regardless of what `phentsize` says, so the
+        // file's entries must actually be that size — otherwise the bounds check below, which is
+        // only spaced by `phentsize`, wouldn't guarantee the read itself stays in bounds.
+        if phentsize != size_of::<ProgramHeader>() {
+            return Err(ElfError::WrongProgramHeaderSize);
+        }
+
+        if phoff.saturating_add(phentsize.saturating_mul(phnum)) > image.len() {
+            return Err(ElfError::TruncatedProgramHeader);
+        }
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let off = phoff + i * phentsize;
+            // SAFETY: the bounds check above guarantees `off + size_of::<ProgramHeader>()` (equal
+            // to `phentsize`, just validated above) is in bounds.
+            let phdr = unsafe { (image.as_ptr().add(off) as *const ProgramHeader).read_unaligned() };
+            if phdr.p_type != PT_LOAD {
+                continue;
+            }
+
+            if phdr.p_filesz > phdr.p_memsz {
+                return Err(ElfError::SegmentFileszExceedsMemsz);
+            }
+            let in_bounds = (phdr.p_offset as usize)
+                .checked_add(phdr.p_filesz as usize)
+                .map_or(false, |end| end <= image.len());
+            if !in_bounds {
+                return Err(ElfError::SegmentOutOfBounds);
+            }
+
+            // Callers round `p_vaddr` down to its containing page and round `p_memsz` up by the
+            // leftover offset, to get a whole number of pages to map. Check that arithmetic can't
+            // overflow here, once, instead of trusting every caller to use checked arithmetic of
+            // its own on a field that came straight off disk.
+            const PAGE_SIZE: u64 = 4096;
+            let page_offset = phdr.p_vaddr % PAGE_SIZE;
+            let rounds_without_overflow = page_offset
+                .checked_add(phdr.p_memsz)
+                .and_then(|v| v.checked_add(PAGE_SIZE - 1))
+                .is_some();
+            if !rounds_without_overflow {
+                return Err(ElfError::SegmentSizeOverflow);
+            }
+
+            segments.push(phdr);
+        }
+
+        Ok(segments)
+    }
+
+    /// The on-disk bytes backing `phdr` (`p_filesz` of them; the rest of `p_memsz` is BSS).
+    ///
+    /// `phdr` must be one returned by `load_segments`, whose bounds against this image are already
+    /// validated.
+    pub fn segment_data(&self, phdr: &ProgramHeader) -> &'a [u8] {
+        let start = phdr.p_offset as usize;
+        let end = start + phdr.p_filesz as usize;
+        &self.image[start..end]
+    }
+}
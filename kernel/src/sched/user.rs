@@ -1,19 +1,29 @@
 //! System calls and kernel <-> user mode switching...
 
+use alloc::vec::Vec;
+
 use x86_64::{
     registers::{
+        control::Cr3,
         model_specific::{Efer, EferFlags, Msr},
         rflags,
     },
-    structures::paging::PageTableFlags,
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
 };
 
 use crate::{
     cap::ResourceHandle,
     interrupts::SELECTORS,
-    memory::{map_region, VirtualMemoryRegion},
+    memory::{
+        mark_executable,
+        paging::{fault, frame_allocator},
+        VirtualMemoryRegion,
+    },
 };
 
+use super::elf::{self, Elf64Image};
+
 const USER_STACK_SIZE: usize = 1; // pages
 
 // Some MSRs used for system call handling.
@@ -27,46 +37,185 @@ const LSTAR: Msr = Msr::new(0xC000_0082);
 /// Contains the kernel rflags mask for syscall.
 const FMASK: Msr = Msr::new(0xC000_0084);
 
-/// Allocates virtual address space, adds appropriate page table mappings, loads the specified code
-/// section into the allocated memory.
+/// The size of [`KERNEL_STACK`], in bytes.
+const KERNEL_STACK_SIZE: usize = 4096 * 4;
+
+/// The kernel stack `handle_syscall` switches to on entry. There is no per-thread kernel stack
+/// yet, so this is just one fixed stack; once the scheduler can hand out one kernel stack per
+/// thread, this should become a per-thread slot instead of a single global.
 ///
-/// Returns the virtual address region where the code has been loaded and the first RIP to start
-/// executing.
-pub fn load_user_code_section() -> (ResourceHandle, usize) {
-    let user_code_section = VirtualMemoryRegion::alloc_with_guard(1).register(); // TODO
-
-    // Map the code section.
-    map_region(
-        user_code_section,
-        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
-    );
+/// `align(16)` so `KERNEL_RSP`, initialized to point at its top, satisfies SysV's 16-byte stack
+/// alignment at the `call dispatch_syscall` in `handle_syscall` — the compiler only guarantees
+/// that alignment at a function's own entry, not at an arbitrary `mov %rsp, $0` mid-`init`.
+#[repr(align(16))]
+struct KernelStack([u8; KERNEL_STACK_SIZE]);
+static mut KERNEL_STACK: KernelStack = KernelStack([0; KERNEL_STACK_SIZE]);
+
+/// The kernel stack `handle_syscall` switches to on entry: the top of [`KERNEL_STACK`].
+///
+/// Referenced by symbol name from the naked asm in `handle_syscall`, since a naked function can't
+/// borrow a Rust value in the normal way.
+#[no_mangle]
+static mut KERNEL_RSP: u64 = 0;
+
+/// Scratch slot `handle_syscall` uses to stash the user `rsp` while the kernel stack is swapped
+/// in, before the full `Context` frame is pushed.
+#[no_mangle]
+static mut USER_RSP_SCRATCH: u64 = 0;
+
+/// Scratch slot `handle_syscall` uses to park the user `rbx` while it borrows that register to
+/// read `cr3`.
+#[no_mangle]
+static mut RBX_SCRATCH: u64 = 0;
+
+/// The full register state of a user thread: every GPR, plus the address space it runs in.
+///
+/// This is what `handle_syscall` saves on syscall entry and restores on exit, and what
+/// `switch_to_user` loads to start (or resume) a thread — both go through the same struct, so a
+/// future timer interrupt can snapshot a running thread's `Context` the same way and resume some
+/// other thread by feeding its saved `Context` back into `switch_to_user`.
+///
+/// `rcx`/`r11` double as the user rip/rflags across `syscall`/`sysret`, per the `syscall`
+/// instruction's contract; there's no separate `rip`/`rflags` field.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Context {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64, // user rflags
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64, // user rip
+    pub rbx: u64,
+    pub rax: u64,
+    pub rsp: u64,
+    /// The physical address of this thread's top-level page table, loaded into `cr3` alongside
+    /// everything else so switching threads also switches address spaces.
+    pub cr3: u64,
+}
 
-    // TODO: load the code
-
-    // TODO: this is test code that is an infinite loop followed by nops
-    let start_addr = user_code_section.with(|cap| {
-        const TEST_CODE: &[u8] = &[
-            0xEB, 0xFE, // here: jmp here
-            0x90, // nop
-            0x90, // nop
-            0x90, // nop
-            0x90, // nop
-            0x90, // nop
-            0x90, // nop
-            0x90, // nop
-            0x90, // nop
-        ];
-
-        unsafe {
+impl Context {
+    /// Build the initial state for a freshly-loaded user thread: every GPR zeroed except `rip`
+    /// (`rcx`) and `rsp`, interrupts enabled, and the current address space (there's only one
+    /// until a `fork`-like syscall exists to create another).
+    pub fn new_user_thread(rip: usize, rsp: usize) -> Context {
+        let (level_4_frame, _) = Cr3::read();
+
+        Context {
+            rcx: rip as u64,
+            rsp: rsp as u64,
+            r11: (rflags::read() | rflags::RFlags::INTERRUPT_FLAG).bits(),
+            cr3: level_4_frame.start_address().as_u64(),
+            ..Context::default()
+        }
+    }
+}
+
+/// Dispatch a system call to its handler.
+///
+/// `num` is the syscall number (from `rax`); `a1..a6` are its arguments, taken from
+/// `rdi`/`rsi`/`rdx`/`r10`/`r8`/`r9` (SysV order, except `r10` standing in for `rcx`, which
+/// `syscall` clobbers with the user rip).
+extern "C" fn dispatch_syscall(
+    num: usize,
+    _a1: usize,
+    _a2: usize,
+    _a3: usize,
+    _a4: usize,
+    _a5: usize,
+    _a6: usize,
+) -> isize {
+    // TODO: real syscall table. For now, every syscall is unimplemented.
+    printk!("unhandled syscall {}", num);
+    -1
+}
+
+/// Allocates virtual address space, adds appropriate page table mappings, loads an ELF64 `image`
+/// into the allocated memory.
+///
+/// Returns the region handle for every `PT_LOAD` segment (a real image typically has more than
+/// one — e.g. separate text and data segments — and the caller needs all of them, to tear the
+/// image down later if nothing else) plus the first RIP to start executing (the image's
+/// `e_entry`).
+///
+/// # Panics
+///
+/// Panics if `image` is not a valid `ET_EXEC`/`ET_DYN` x86-64 ELF64 image.
+pub fn load_user_code_section(image: &[u8]) -> (Vec<ResourceHandle>, usize) {
+    let elf = Elf64Image::parse(image).expect("not a loadable x86-64 ELF64 image");
+
+    let mut code_regions = Vec::new();
+
+    for phdr in elf.load_segments().expect("malformed program header table") {
+        // The segment's `p_vaddr` need not be page-aligned; round down to the containing page and
+        // carry the offset through so the data lands at the right spot within it.
+        let page_size = Size4KiB::SIZE as usize;
+        let page_offset = phdr.p_vaddr as usize % page_size;
+        let num_pages = (page_offset + phdr.p_memsz as usize + page_size - 1) / page_size;
+        let seg_start = phdr.p_vaddr as usize - page_offset;
+        let executable = phdr.p_flags & elf::PF_X != 0;
+
+        // The number of pages holding at least one byte of file data; these need a real frame
+        // right now, to copy that data into. Pages past them are pure BSS and would normally be
+        // left demand-paged — except `mark_executable` (below) requires every page in the region
+        // to already be mapped, so executable segments populate the whole region up front
+        // instead.
+        let data_pages = (page_offset + phdr.p_filesz as usize + page_size - 1) / page_size;
+        let eager_pages = if executable { num_pages } else { data_pages };
+
+        let region = VirtualMemoryRegion::alloc_at(seg_start, num_pages).register();
+
+        // Every segment is writable while we copy its bytes in, regardless of its final
+        // permissions, and non-executable while it is; `mark_executable` below swaps that for
+        // read-only and executable on executable segments, so a page is never simultaneously
+        // writable and executable.
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::USER_ACCESSIBLE
+            | PageTableFlags::NO_EXECUTE;
+        fault::register_lazy(region, flags);
+
+        region.with(|cap| unsafe {
             let start = cap_unwrap!(VirtualMemoryRegion(cap)).start();
-            for (i, b) in TEST_CODE.iter().enumerate() {
-                start.offset(i as isize).write(*b);
+
+            // Fault in the pages that need real content now, reusing `handle_page_fault`'s own
+            // frame-allocating/zeroing path rather than a second copy of it. Whole pages past
+            // `eager_pages` are left pending: the first actual access zero-fills them, which is
+            // exactly what BSS needs.
+            let frames = frame_allocator::frame_allocator();
+            for i in 0..eager_pages {
+                fault::populate_now(VirtAddr::from_ptr(start.add(i * page_size)), frames);
             }
-            start as usize
+
+            let seg_base = start.add(page_offset);
+            let data = elf.segment_data(&phdr);
+            core::ptr::copy_nonoverlapping(data.as_ptr(), seg_base, data.len());
+
+            // Zero the part of `p_memsz` that shares an eagerly-populated page with file data;
+            // whole BSS pages beyond `eager_pages` are already zero-filled on demand.
+            let zero_len = (eager_pages * page_size).saturating_sub(page_offset + data.len());
+            core::ptr::write_bytes(seg_base.add(data.len()), 0, zero_len);
+        });
+
+        if executable {
+            mark_executable(region);
         }
-    });
+        // Non-executable segments are left in the writable state `register_lazy`/`populate_now`
+        // above already put them in; there's no `mark_read_only` yet, so read-only data segments
+        // stay writable for now.
+
+        code_regions.push(region);
+    }
 
-    (user_code_section, start_addr)
+    assert!(!code_regions.is_empty(), "ELF image has no PT_LOAD segments");
+    (code_regions, elf.entry() as usize)
 }
 
 /// Allocates virtual address space for the user stack (fixed size). Adds appropriate page table
@@ -79,8 +228,9 @@ pub fn allocate_user_stack() -> ResourceHandle {
     // Allocate the stack the user will run on.
     let user_stack = VirtualMemoryRegion::alloc_with_guard(USER_STACK_SIZE).register();
 
-    // Map the stack into the address space.
-    map_region(
+    // Nothing needs to be written to the stack up front, so leave every page demand-paged:
+    // `handle_page_fault` backs each page with a fresh, zeroed frame the first time it's touched.
+    fault::register_lazy(
         user_stack,
         PageTableFlags::PRESENT
             | PageTableFlags::WRITABLE
@@ -112,90 +262,167 @@ pub fn init() {
         // LSTAR: Syscall Entry RIP
         LSTAR.write(handle_syscall as u64);
 
-        // FMASK: rflags mask: any set bits are cleared on syscall
-        FMASK.write(0);
+        // FMASK: rflags mask: any set bits are cleared on syscall. Mask the interrupt flag so we
+        // enter `handle_syscall` with interrupts off; it re-enables them once it's safely on the
+        // kernel stack.
+        FMASK.write(rflags::RFlags::INTERRUPT_FLAG.bits());
+
+        // Point the syscall entry stack at the top of KERNEL_STACK (stacks grow down), rather
+        // than sampling `init`'s own live `rsp` — the compiler doesn't guarantee that's 16-byte
+        // aligned at this point mid-function, and `handle_syscall` needs it to be.
+        KERNEL_RSP = KERNEL_STACK.0.as_mut_ptr().add(KERNEL_STACK_SIZE) as u64;
     }
 }
 
-/// Switch to user mode, executing the given code with the given address.
-pub fn switch_to_user(code: (ResourceHandle, usize), stack: ResourceHandle) -> ! {
-    // Compute new register values
-    let rsp = stack.with(|cap| {
-        let region = cap_unwrap!(VirtualMemoryRegion(cap));
-        let start = region.start();
-        let len = region.len();
-        unsafe { start.offset(len as isize) }
-    });
-
-    let (_handle, rip) = code;
-
-    let rflags = (rflags::read() | rflags::RFlags::INTERRUPT_FLAG).bits();
+/// Loads every field of the `Context` pointed to by `%rdi` into its register and returns to user
+/// mode with `sysret`. Shared verbatim by `switch_to_user` (entering/resuming a thread from Rust)
+/// and `handle_syscall`'s return path (resuming the thread a syscall just came from), so there is
+/// exactly one place that knows `Context`'s field offsets.
+macro_rules! restore_context_and_sysret {
+    () => {
+        "
+        # switch address spaces first: nothing below this point is valid to dereference in the
+        # old one anyway (the new user rsp, the new rip, ...). rax is scratch here; its real value
+        # is loaded below along with everything else.
+        mov 128(%rdi), %rax
+        mov %rax, %cr3
+
+        mov 0(%rdi) , %r15
+        mov 8(%rdi) , %r14
+        mov 16(%rdi), %r13
+        mov 24(%rdi), %r12
+        mov 32(%rdi), %r11  # needed for sysret
+        mov 40(%rdi), %r10
+        mov 48(%rdi), %r9
+        mov 56(%rdi), %r8
+        mov 64(%rdi), %rbp
+        mov 88(%rdi), %rdx
+        mov 96(%rdi), %rcx  # needed for sysret
+        mov 104(%rdi), %rbx
+        mov 112(%rdi), %rax
+        mov 80(%rdi), %rsi
+
+        # disable interrupts before loading the user stack; otherwise, an interrupt may be
+        # serviced on the wrong stack.
+        cli
+
+        # no more stack refs until sysret
+        mov 120(%rdi), %rsp
+
+        # rdi last: it's both the pointer we've been reading through and one of the registers
+        # we're restoring, so its own field has to be loaded after everything else.
+        mov 72(%rdi), %rdi
+
+        # return to usermode (ring 3)
+        sysret
+        "
+    };
+}
 
-    // TODO: save kernel stack location somewhere so that we can switch back to it during an
-    // interrupt. Or do we need to? The scheduler already knows where its two stacks are... can we
-    // just wipe one of them and use it?
+/// Switch to user mode (or resume an already-running user thread), loading every register —
+/// including the address space — from `context` rather than starting from a blank slate.
+///
+/// # Safety
+///
+/// `context.cr3` must point at a valid, fully set-up top-level page table for the thread being
+/// resumed; loading a bad one takes down the whole machine, not just this thread.
+pub unsafe fn switch_to_user(context: &Context) -> ! {
+    // TODO: save the current kernel stack location somewhere so that we can switch back to it on
+    // the next syscall/interrupt from this thread. Or do we need to? The scheduler already knows
+    // where its two stacks are... can we just wipe one of them and use it?
 
     // https://software.intel.com/sites/default/files/managed/39/c5/325462-sdm-vol-1-2abcd-3abcd.pdf#G43.25974
     //
-    // Set the following and execute the `sysret` instruction:
-    // - user rip: load into rcx before sysret
-    // - rflags: load into r11 before sysret
-    // - also want to set any register values to be given to the user
-    //      - user rsp
-    //      - clear all other regs
-    //
-    // TODO: eventually we may want to have a general mechanism for restoring registers to know
-    // values from a struct or something. For now, we just clear all registers.
-    unsafe {
-        asm!(
-            "
-            # needed for sysret
-            mov $0, %rcx
-            mov $1, %r11
-
-            # clear other regs
-            xor %rax, %rax
-            xor %rbx, %rbx
-            xor %rdx, %rdx
-            xor %rdi, %rdi
-            xor %rsi, %rsi
-            xor %r8 , %r8
-            xor %r9 , %r9
-            xor %r10, %r10
-            xor %r12, %r12
-            xor %r13, %r13
-            xor %r14, %r14
-            xor %r15, %r15
-
-            # disable interrupts before loading the user stack; otherwise, an interrupt may be
-            # serviced on the wrong stack.
-            cli
-
-            # no more stack refs until sysret
-            mov $2, %rsp
-
-            # return to usermode (ring 3)
-            sysret
-            "
-            : /* no outputs */
-            : "r"(rip), "r"(rflags), "r"(rsp)
-            : "rcx", "r1", "memory"
-            : "volatile"
-        );
-    }
+    // Rather than juggling one operand per register (`Context` has 17 fields — more live values
+    // than there are GPRs to hold them once the clobbered ones are off limits), pass a pointer to
+    // `context` in `rdi` and let the shared restore sequence load every field from it by offset.
+    asm!(
+        restore_context_and_sysret!()
+        : /* no outputs */
+        : "{rdi}"(context as *const Context as u64)
+        : "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "r8", "r9", "r10", "r11", "r12", "r13",
+          "r14", "r15", "memory"
+        : "volatile"
+    );
 
     unreachable!();
 }
 
-/// Handle a `syscall` instruction
+/// Handle a `syscall` instruction.
+///
+/// On entry: `rcx` holds the user rip, `r11` holds the user rflags (both clobbered by `syscall`
+/// itself), `rsp` is still the user stack, and interrupts are off (masked by `FMASK`). This naked
+/// stub swaps to the kernel stack and pushes a genuine `Context` frame (same field order
+/// `switch_to_user` reads), so the two share one restore routine and a future timer handler can
+/// snapshot this frame as `&Context` directly. It then calls into `dispatch_syscall`, writes the
+/// return value into the frame's `rax` slot, and falls into `restore_context_and_sysret!` to
+/// return to user mode.
+///
+/// https://software.intel.com/sites/default/files/managed/39/c5/325462-sdm-vol-1-2abcd-3abcd.pdf#G43.25974
 #[naked]
 extern "C" fn handle_syscall() {
-    // TODO: switch to kernel stack, save user regs
-    //
-    // https://software.intel.com/sites/default/files/managed/39/c5/325462-sdm-vol-1-2abcd-3abcd.pdf#G43.25974
-    //
-    // TODO: for syscall handling: see the warnings at the end of the above chapter in the Intel
-    // SDM (e.g. regarding interrupts, user stack)
-
-    todo!("syscall");
+    unsafe {
+        asm!(
+            concat!(
+                "
+                # Stash the user rsp and load the known kernel stack. Until this point we must
+                # not touch the stack at all, since it is still the user's.
+                mov %rsp, USER_RSP_SCRATCH(%rip)
+                mov KERNEL_RSP(%rip), %rsp
+
+                # Push a Context frame, highest offset first, so it ends up laid out exactly like
+                # the struct (rsp afterwards == &Context). rbx is scratch for reading cr3 in the
+                # meantime: its real value is parked in RBX_SCRATCH and pushed back in its own
+                # slot below.
+                mov %rbx, RBX_SCRATCH(%rip)
+                mov %cr3, %rbx
+                push %rbx                      # cr3
+                push USER_RSP_SCRATCH(%rip)     # rsp
+                push %rax
+                push RBX_SCRATCH(%rip)          # rbx
+                push %rcx                       # user rip
+                push %rdx
+                push %rsi
+                push %rdi
+                push %rbp
+                push %r8
+                push %r9
+                push %r10
+                push %r11                       # user rflags
+                push %r12
+                push %r13
+                push %r14
+                push %r15
+
+                # Now that we are fully on the kernel stack, it is safe to take interrupts again.
+                sti
+
+                # Shuffle the syscall calling convention (rax = num, rdi/rsi/rdx/r10/r8/r9 = args)
+                # into the SysV C calling convention `dispatch_syscall` expects (rdi, rsi, rdx,
+                # rcx, r8, r9, then the 7th arg on the stack). The Context push above only copied
+                # these registers onto the stack, so they're all still live; work from the end of
+                # each chain backwards so no source is clobbered before it's read.
+                push %r9
+                mov %r8, %r9
+                mov %r10, %r8
+                mov %rdx, %rcx
+                mov %rsi, %rdx
+                mov %rdi, %rsi
+                mov %rax, %rdi
+                call dispatch_syscall
+                add $$8, %rsp
+
+                # Overwrite the Context's saved rax with dispatch_syscall's return value, then
+                # hand the frame to the restore routine `switch_to_user` also uses.
+                mov %rax, 112(%rsp)
+                mov %rsp, %rdi
+                "
+                , restore_context_and_sysret!()
+            )
+            : /* no outputs */
+            : /* no inputs: everything crosses via the statics and the stack */
+            : "memory"
+            : "volatile"
+        );
+    }
 }